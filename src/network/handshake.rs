@@ -0,0 +1,421 @@
+//! Secret-handshake authentication and transport encryption for peer
+//! connections, performed on the raw `TcpStream` before `NodeCodec` framing
+//! is installed. Based on the Dominic Tarr "secret-handshake" scheme: the
+//! cluster shares a symmetric network key, and each node additionally holds
+//! a long-term ed25519 keypair whose public key is its verifiable identity.
+//!
+//! Message flow (initiator `I` is the side opening the connection, responder
+//! `R` is the side accepting it):
+//!
+//! 1. `I -> R`: ephemeral X25519 public key, HMAC-authenticated under the
+//!    network key.
+//! 2. `R -> I`: `R`'s ephemeral X25519 public key, authenticated the same way.
+//!    Both sides now derive `shared = X25519(eph_priv, peer_eph_pub)` and hash
+//!    it together with the network key to get the handshake key `hs_key`.
+//! 3. `I -> R`: `I`'s long-term ed25519 public key and a signature over
+//!    `network_key || R_long_term_pk || I_ephemeral_pk`, boxed (AEAD-sealed,
+//!    not just tagged) under `hs_key` so a passive observer can't read either
+//!    side's long-term identity off the wire. `I` must already know `R`'s
+//!    long-term public key to form this (it's the side dialing a specific
+//!    peer); `R` learns `I`'s identity from this message.
+//! 4. `R -> I`: verifies (3), then replies with its own long-term public key
+//!    and a signature over `network_key || I_long_term_pk || R_ephemeral_pk`,
+//!    boxed the same way.
+//!
+//! On success both sides derive a *pair* of directional `SessionKey`s from
+//! `hs_key` and the two long-term public keys — one for `I -> R` traffic, one
+//! for `R -> I` — so the two directions of a session never encrypt under the
+//! same (key, nonce) pair even though each direction's nonce counter starts
+//! at 0 independently. Any failure to authenticate the network key or the
+//! peer's signature drops the connection without ever installing `NodeCodec`.
+
+use std::collections::HashMap;
+use std::io;
+
+use actix_raft::NodeId;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Keypair as EdKeypair, PublicKey as EdPublicKey, Signature, Signer, Verifier};
+use futures::Future;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::io::{read_exact, write_all, AsyncRead, AsyncWrite};
+use x25519_dalek::{EphemeralSecret, PublicKey as XPublicKey};
+
+const EPHEMERAL_PK_LEN: usize = 32;
+const HMAC_LEN: usize = 32;
+const ED25519_PK_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// Poly1305 authentication tag appended by `ChaCha20Poly1305` to every box.
+const AEAD_TAG_LEN: usize = 16;
+
+/// Key shared by every node in the cluster. Connections that can't prove
+/// knowledge of it are dropped before any `NodeId` is ever trusted.
+#[derive(Clone)]
+pub struct NetworkKey(pub [u8; 32]);
+
+/// This node's long-term identity, plus the public keys of peers it's
+/// willing to accept connections from, keyed to their `NodeId`.
+pub struct Identity {
+    pub keypair: EdKeypair,
+    pub known_peers: HashMap<[u8; ED25519_PK_LEN], NodeId>,
+}
+
+/// A symmetric key derived at the end of a successful handshake, used to
+/// seal/open every frame sent in one direction of a session. Never shared
+/// between the two directions of the same session — see module docs.
+#[derive(Clone)]
+pub struct SessionKey(pub [u8; 32]);
+
+/// Outcome of a successful handshake: the stream (handshake bytes already
+/// consumed), the authenticated peer id, and the pair of directional keys
+/// this end of the session should use — `send_key` for frames going out to
+/// the peer, `recv_key` for frames coming in from it.
+pub struct Handshaken<S> {
+    pub stream: S,
+    pub peer_id: NodeId,
+    pub send_key: SessionKey,
+    pub recv_key: SessionKey,
+}
+
+fn hmac_tag(key: &[u8], msg: &[u8]) -> [u8; HMAC_LEN] {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.input(msg);
+    let mut out = [0u8; HMAC_LEN];
+    out.copy_from_slice(&mac.result().code());
+    out
+}
+
+fn verify_hmac(key: &[u8], msg: &[u8], tag: &[u8]) -> bool {
+    hmac_tag(key, msg).as_ref() == tag
+}
+
+fn derive_handshake_key(network_key: &NetworkKey, shared_secret: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(&network_key.0);
+    hasher.input(shared_secret);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hasher.result());
+    out
+}
+
+/// Derives the key for traffic flowing `from_pk -> to_pk` on this session.
+/// Keying the hash on the ordered (sender, recipient) pair is what makes the
+/// two directions of a session get distinct keys even though both sides
+/// compute `hs_key` identically: `A`'s send key is `B`'s recv key and
+/// vice versa, but `A`'s send key and `A`'s recv key are never equal.
+fn derive_directional_key(hs_key: &[u8; 32], from_pk: &[u8], to_pk: &[u8]) -> SessionKey {
+    let mut hasher = Sha256::new();
+    hasher.input(hs_key);
+    hasher.input(from_pk);
+    hasher.input(to_pk);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hasher.result());
+    SessionKey(out)
+}
+
+/// Authenticates `payload` under `key` with a fixed-purpose HMAC tag, without
+/// hiding its contents. Used only for the ephemeral public keys in messages
+/// 1/2, which aren't secret — they just need to be provably sent by someone
+/// who knows the network key.
+fn seal_handshake_msg(key: &[u8; 32], payload: &[u8]) -> Vec<u8> {
+    let tag = hmac_tag(key, payload);
+    let mut out = Vec::with_capacity(HMAC_LEN + payload.len());
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn open_handshake_msg(key: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < HMAC_LEN {
+        return None;
+    }
+    let (tag, payload) = sealed.split_at(HMAC_LEN);
+    if verify_hmac(key, payload, tag) {
+        Some(payload.to_vec())
+    } else {
+        None
+    }
+}
+
+/// AEAD-seals `payload` under `key` (the handshake key `hs_key`) so it's
+/// hidden from passive observers, not just authenticated — used for messages
+/// 3/4, which carry each side's long-term identity and a signature over it.
+/// `hs_key` is freshly derived per handshake and each of messages 3/4 uses
+/// its own fixed nonce, so a (key, nonce) pair is never reused.
+fn box_handshake_msg(key: &[u8; 32], nonce: u8, payload: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[0] = nonce;
+    cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), payload)
+        .expect("chacha20poly1305 encryption does not fail for valid inputs")
+}
+
+fn open_boxed_handshake_msg(key: &[u8; 32], nonce: u8, sealed: &[u8]) -> Option<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[0] = nonce;
+    cipher.decrypt(Nonce::from_slice(&nonce_bytes), sealed).ok()
+}
+
+/// Nonce used to box message 3 (initiator's identity) under `hs_key`.
+const MSG3_NONCE: u8 = 3;
+/// Nonce used to box message 4 (responder's identity) under `hs_key`.
+const MSG4_NONCE: u8 = 4;
+
+fn auth_failure(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("secret-handshake: {}", what))
+}
+
+/// Runs the responder side of the handshake (the accepting end of a
+/// `TcpListener::incoming()` connection).
+pub fn respond<S>(
+    stream: S,
+    network_key: NetworkKey,
+    identity: Identity,
+) -> Box<dyn Future<Item = Handshaken<S>, Error = io::Error> + Send>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let our_eph_secret = EphemeralSecret::new(&mut rand::rngs::OsRng);
+    let our_eph_public = XPublicKey::from(&our_eph_secret);
+
+    // 1. Receive and authenticate the initiator's ephemeral key.
+    Box::new(
+        read_exact(stream, vec![0u8; HMAC_LEN + EPHEMERAL_PK_LEN])
+            .and_then(move |(stream, msg1)| {
+                let eph_pub = match open_handshake_msg(&network_key.0, &msg1) {
+                    Some(payload) => payload,
+                    None => return Err(auth_failure("invalid network key on message 1")),
+                };
+                let mut buf = [0u8; EPHEMERAL_PK_LEN];
+                buf.copy_from_slice(&eph_pub);
+                Ok((stream, XPublicKey::from(buf), network_key))
+            })
+            .and_then(move |(stream, their_eph_pub, network_key)| {
+                // 2. Reply with our own authenticated ephemeral key.
+                let msg2 = seal_handshake_msg(&network_key.0, our_eph_public.as_bytes());
+                write_all(stream, msg2).map(move |(stream, _)| {
+                    let shared = our_eph_secret.diffie_hellman(&their_eph_pub);
+                    let hs_key = derive_handshake_key(&network_key, shared.as_bytes());
+                    (stream, hs_key, network_key, their_eph_pub, our_eph_public)
+                })
+            })
+            .and_then(move |(stream, hs_key, network_key, their_eph_pub, our_eph_public)| {
+                // 3. Receive the initiator's long-term public key + signature,
+                // boxed (not just tagged) under `hs_key` so it's hidden from
+                // a passive observer.
+                read_exact(stream, vec![0u8; ED25519_PK_LEN + SIGNATURE_LEN + AEAD_TAG_LEN]).and_then(
+                    move |(stream, msg3)| {
+                        let payload = open_boxed_handshake_msg(&hs_key, MSG3_NONCE, &msg3)
+                            .ok_or_else(|| auth_failure("invalid handshake key on message 3"))?;
+                        let (pk_bytes, sig_bytes) = payload.split_at(ED25519_PK_LEN);
+                        let their_pk = EdPublicKey::from_bytes(pk_bytes)
+                            .map_err(|_| auth_failure("malformed long-term public key"))?;
+                        let sig = Signature::from_bytes(sig_bytes)
+                            .map_err(|_| auth_failure("malformed signature"))?;
+
+                        // Signed tuple must match what `initiate` signs:
+                        // (network key, the recipient's own pk, the signer's
+                        // ephemeral pk as seen by the recipient).
+                        let mut to_verify = Vec::new();
+                        to_verify.extend_from_slice(&network_key.0);
+                        to_verify.extend_from_slice(identity.keypair.public.as_bytes());
+                        to_verify.extend_from_slice(their_eph_pub.as_bytes());
+                        their_pk
+                            .verify(&to_verify, &sig)
+                            .map_err(|_| auth_failure("signature verification failed"))?;
+
+                        let mut key_bytes = [0u8; ED25519_PK_LEN];
+                        key_bytes.copy_from_slice(pk_bytes);
+                        let peer_id = *identity
+                            .known_peers
+                            .get(&key_bytes)
+                            .ok_or_else(|| auth_failure("public key is not a known cluster member"))?;
+
+                        Ok((stream, hs_key, network_key, their_pk, peer_id, our_eph_public, identity))
+                    },
+                )
+            })
+            .and_then(move |(stream, hs_key, network_key, their_pk, peer_id, our_eph_public, identity)| {
+                // 4. Sign back and box our reply under the handshake key.
+                // Signs (network key, the initiator's pk, our own ephemeral
+                // pk) so `initiate`'s verification — (network key, its own
+                // pk, the ephemeral pk it received in message 2) — lines up
+                // byte-for-byte.
+                let our_pk = identity.keypair.public;
+                let mut to_sign = Vec::new();
+                to_sign.extend_from_slice(&network_key.0);
+                to_sign.extend_from_slice(their_pk.as_bytes());
+                to_sign.extend_from_slice(our_eph_public.as_bytes());
+                let signature = identity.keypair.sign(&to_sign);
+
+                let mut payload = Vec::new();
+                payload.extend_from_slice(our_pk.as_bytes());
+                payload.extend_from_slice(&signature.to_bytes());
+                let msg4 = box_handshake_msg(&hs_key, MSG4_NONCE, &payload);
+
+                write_all(stream, msg4).map(move |(stream, _)| {
+                    // We're the responder (`R`): our send direction is R -> I,
+                    // our receive direction is I -> R.
+                    let send_key = derive_directional_key(&hs_key, our_pk.as_bytes(), their_pk.as_bytes());
+                    let recv_key = derive_directional_key(&hs_key, their_pk.as_bytes(), our_pk.as_bytes());
+                    Handshaken { stream, peer_id, send_key, recv_key }
+                })
+            }),
+    )
+}
+
+/// Runs the initiator side of the handshake (the dialing end of a
+/// connection to a peer's listener). Mirrors `respond`, with messages 1/3
+/// sent and 2/4 received instead of the other way around.
+///
+/// Unlike `respond`, which learns the peer's long-term public key from
+/// message 3 itself and looks up its `NodeId` in `identity.known_peers`,
+/// the initiator must already know who it's dialing: `peer_public_key` is
+/// the expected responder's long-term public key (e.g. from cluster
+/// config), both signed over in message 3 and checked against message 4's
+/// claimed identity.
+pub fn initiate<S>(
+    stream: S,
+    network_key: NetworkKey,
+    identity: Identity,
+    peer_id: NodeId,
+    peer_public_key: [u8; ED25519_PK_LEN],
+) -> Box<dyn Future<Item = Handshaken<S>, Error = io::Error> + Send>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let our_eph_secret = EphemeralSecret::new(&mut rand::rngs::OsRng);
+    let our_eph_public = XPublicKey::from(&our_eph_secret);
+
+    // 1. Send our authenticated ephemeral key.
+    let msg1 = seal_handshake_msg(&network_key.0, our_eph_public.as_bytes());
+    Box::new(
+        write_all(stream, msg1)
+            .and_then(move |(stream, _)| {
+                // 2. Receive and authenticate the responder's ephemeral key.
+                read_exact(stream, vec![0u8; HMAC_LEN + EPHEMERAL_PK_LEN]).and_then(
+                    move |(stream, msg2)| {
+                        let eph_pub = open_handshake_msg(&network_key.0, &msg2)
+                            .ok_or_else(|| auth_failure("invalid network key on message 2"))?;
+                        let mut buf = [0u8; EPHEMERAL_PK_LEN];
+                        buf.copy_from_slice(&eph_pub);
+                        let their_eph_pub = XPublicKey::from(buf);
+                        let shared = our_eph_secret.diffie_hellman(&their_eph_pub);
+                        let hs_key = derive_handshake_key(&network_key, shared.as_bytes());
+                        Ok((stream, hs_key, network_key, their_eph_pub))
+                    },
+                )
+            })
+            .and_then(move |(stream, hs_key, network_key, their_eph_pub)| {
+                // 3. Sign our long-term identity and box it under `hs_key`.
+                // Signed tuple must match what `respond` verifies:
+                // (network key, the recipient's own pk, the signer's
+                // ephemeral pk as seen by the recipient) — here the
+                // recipient is the responder, so that's `peer_public_key`
+                // and our own ephemeral key.
+                let our_pk = identity.keypair.public;
+                let mut to_sign = Vec::new();
+                to_sign.extend_from_slice(&network_key.0);
+                to_sign.extend_from_slice(&peer_public_key);
+                to_sign.extend_from_slice(our_eph_public.as_bytes());
+                let signature = identity.keypair.sign(&to_sign);
+
+                let mut payload = Vec::new();
+                payload.extend_from_slice(our_pk.as_bytes());
+                payload.extend_from_slice(&signature.to_bytes());
+                let msg3 = box_handshake_msg(&hs_key, MSG3_NONCE, &payload);
+
+                write_all(stream, msg3).map(move |(stream, _)| (stream, hs_key, network_key, our_pk, their_eph_pub))
+            })
+            .and_then(move |(stream, hs_key, network_key, our_pk, their_eph_pub)| {
+                // 4. Receive and verify the responder's signed reply.
+                read_exact(stream, vec![0u8; ED25519_PK_LEN + SIGNATURE_LEN + AEAD_TAG_LEN]).and_then(
+                    move |(stream, msg4)| {
+                        let payload = open_boxed_handshake_msg(&hs_key, MSG4_NONCE, &msg4)
+                            .ok_or_else(|| auth_failure("invalid handshake key on message 4"))?;
+                        let (pk_bytes, sig_bytes) = payload.split_at(ED25519_PK_LEN);
+                        if pk_bytes != &peer_public_key[..] {
+                            return Err(auth_failure("responder's public key does not match the expected peer"));
+                        }
+                        let their_pk = EdPublicKey::from_bytes(pk_bytes)
+                            .map_err(|_| auth_failure("malformed long-term public key"))?;
+                        let sig = Signature::from_bytes(sig_bytes)
+                            .map_err(|_| auth_failure("malformed signature"))?;
+
+                        // Must match what `respond` signed: (network key,
+                        // our own pk, the responder's own ephemeral pk,
+                        // i.e. the `their_eph_pub` we received in message 2).
+                        let mut to_verify = Vec::new();
+                        to_verify.extend_from_slice(&network_key.0);
+                        to_verify.extend_from_slice(our_pk.as_bytes());
+                        to_verify.extend_from_slice(their_eph_pub.as_bytes());
+                        their_pk
+                            .verify(&to_verify, &sig)
+                            .map_err(|_| auth_failure("signature verification failed"))?;
+
+                        // We're the initiator (`I`): our send direction is
+                        // I -> R, our receive direction is R -> I.
+                        let send_key = derive_directional_key(&hs_key, our_pk.as_bytes(), their_pk.as_bytes());
+                        let recv_key = derive_directional_key(&hs_key, their_pk.as_bytes(), our_pk.as_bytes());
+                        Ok(Handshaken { stream, peer_id, send_key, recv_key })
+                    },
+                )
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    fn identity() -> Identity {
+        Identity {
+            keypair: EdKeypair::generate(&mut rand::rngs::OsRng),
+            known_peers: HashMap::new(),
+        }
+    }
+
+    /// Regression test for a bug where `initiate` and `respond` signed/
+    /// verified different byte strings for messages 3/4, so two distinct
+    /// nodes could never complete a handshake with each other. Runs both
+    /// sides over a real connected socket pair and checks they agree on
+    /// both the peer's identity and the session keys.
+    #[test]
+    fn initiate_and_respond_agree_on_peer_and_keys() {
+        let (initiator_stream, responder_stream) = UnixStream::pair().unwrap();
+
+        let network_key = NetworkKey([7u8; 32]);
+        let initiator_id: NodeId = 1;
+        let responder_id: NodeId = 2;
+
+        let initiator_identity = identity();
+        let initiator_pk = initiator_identity.keypair.public.to_bytes();
+
+        let mut responder_identity = identity();
+        let responder_pk = responder_identity.keypair.public.to_bytes();
+        responder_identity.known_peers.insert(initiator_pk, initiator_id);
+
+        let respond_fut = respond(responder_stream, network_key.clone(), responder_identity);
+        let initiate_fut = initiate(
+            initiator_stream,
+            network_key,
+            initiator_identity,
+            responder_id,
+            responder_pk,
+        );
+
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let (responder_result, initiator_result) = rt
+            .block_on(respond_fut.join(initiate_fut))
+            .expect("handshake should succeed on both ends");
+
+        assert_eq!(responder_result.peer_id, initiator_id);
+        assert_eq!(initiator_result.peer_id, responder_id);
+        assert_eq!(responder_result.send_key.0, initiator_result.recv_key.0);
+        assert_eq!(responder_result.recv_key.0, initiator_result.send_key.0);
+    }
+}
@@ -7,22 +7,29 @@ use actix_raft::{
     NodeId,
     messages,
 };
+use std::cell::Cell;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::marker::PhantomData;
 use std::collections::HashMap;
 use serde::{Serialize, de::DeserializeOwned};
+use futures::Future;
 
 use crate::raft::{
     MemRaft,
     storage
 };
 use crate::network::{
+    Codec,
     Network,
-    NodeCodec,
     NodeRequest,
     NodeResponse,
     PeerConnected,
+    SecureNodeCodec,
+    handshake::{self, Identity, NetworkKey},
+    metrics::{GetSessionStats, PeerDisconnected, TrafficCounters},
     remote::{
+        HandlerRegistry,
         RemoteMessageHandler,
         RegisterHandler,
         RemoteMessage,
@@ -30,13 +37,45 @@ use crate::network::{
     },
 };
 
+impl RemoteMessage for messages::AppendEntriesRequest<storage::MemoryStorageData> {
+    const TYPE_ID: &'static str = "AppendEntriesRequest";
+}
+
+impl RemoteMessage for messages::VoteRequest {
+    const TYPE_ID: &'static str = "VoteRequest";
+}
+
+impl RemoteMessage for messages::InstallSnapshotRequest {
+    const TYPE_ID: &'static str = "InstallSnapshotRequest";
+}
+
 pub struct Listener {
     network: Addr<Network>,
     raft: Option<Addr<MemRaft>>,
+    network_key: Arc<NetworkKey>,
+    identity: Arc<Identity>,
+    drain_deadline: Duration,
 }
 
 impl Listener {
-    pub fn new(address: &str, network_addr: Addr<Network>) -> Addr<Listener> {
+    pub fn new(
+        address: &str,
+        network_addr: Addr<Network>,
+        network_key: NetworkKey,
+        identity: Identity,
+    ) -> Addr<Listener> {
+        Listener::with_drain_deadline(address, network_addr, network_key, identity, DEFAULT_DRAIN_DEADLINE)
+    }
+
+    /// Like [`Listener::new`], but overrides how long a draining session
+    /// waits for in-flight requests to finish before force-stopping.
+    pub fn with_drain_deadline(
+        address: &str,
+        network_addr: Addr<Network>,
+        network_key: NetworkKey,
+        identity: Identity,
+        drain_deadline: Duration,
+    ) -> Addr<Listener> {
         let server_addr = address.parse().unwrap();
         let listener = TcpListener::bind(&server_addr).unwrap();
 
@@ -46,6 +85,9 @@ impl Listener {
             Listener {
                 network: network_addr,
                 raft: None,
+                network_key: Arc::new(network_key),
+                identity: Arc::new(identity),
+                drain_deadline,
             }
         })
     }
@@ -62,15 +104,44 @@ impl Handler<NodeConnect> for Listener {
     type Result = ();
 
     fn handle(&mut self, msg: NodeConnect, _: &mut Context<Self>) {
-        let remote_addr = msg.0.peer_addr().unwrap();
-        let (r, w) = msg.0.split();
-
         let network = self.network.clone();
+        let network_key = (*self.network_key).clone();
+        let identity = Identity {
+            keypair: ed25519_dalek::Keypair::from_bytes(&self.identity.keypair.to_bytes()).unwrap(),
+            known_peers: self.identity.known_peers.clone(),
+        };
+        let drain_deadline = self.drain_deadline;
 
-        NodeSession::create(move |ctx| {
-            NodeSession::add_stream(FramedRead::new(r, NodeCodec), ctx);
-            NodeSession::new(actix::io::FramedWrite::new(w, NodeCodec, ctx), network)
-        });
+        // Authenticate and derive a session key before any `NodeRequest` is
+        // ever trusted; only on success do we install the framed codec and
+        // spin up a `NodeSession`.
+        let task = handshake::respond(msg.0, network_key, identity)
+            .map(move |handshaken| {
+                let handshake::Handshaken { stream, peer_id, send_key, recv_key } = handshaken;
+                let (r, w) = stream.split();
+
+                NodeSession::create(move |ctx| {
+                    NodeSession::add_stream(
+                        FramedRead::new(r, SecureNodeCodec::new(recv_key, Rc::new(Cell::new(0)))),
+                        ctx,
+                    );
+                    let out_frame_len = Rc::new(Cell::new(0));
+                    let mut session = NodeSession::new(
+                        actix::io::FramedWrite::new(w, SecureNodeCodec::new(send_key, out_frame_len.clone()), ctx),
+                        network.clone(),
+                        drain_deadline,
+                        out_frame_len,
+                    );
+                    session.id = Some(peer_id);
+                    network.do_send(PeerConnected(peer_id, ctx.address()));
+                    session
+                });
+            })
+            .map_err(|err| {
+                println!("Rejecting peer connection: {}", err);
+            });
+
+        actix::spawn(task);
     }
 }
 
@@ -80,45 +151,159 @@ pub struct RaftCreated(pub Addr<MemRaft>);
 impl Handler<RaftCreated> for NodeSession {
     type Result = ();
 
-    fn handle(&mut self, msg: RaftCreated, ctx: &mut Context<Self>) {
-        self.raft = Some(msg.0);
+    fn handle(&mut self, msg: RaftCreated, _: &mut Context<Self>) {
+        let raft = msg.0;
+        self.handlers.register::<messages::AppendEntriesRequest<storage::MemoryStorageData>>(Arc::new(raft.clone()));
+        self.handlers.register::<messages::VoteRequest>(Arc::new(raft.clone()));
+        self.handlers.register::<messages::InstallSnapshotRequest>(Arc::new(raft.clone()));
+        self.raft = Some(raft);
+    }
+}
+
+impl<M> Handler<RegisterHandler<M>> for NodeSession
+where
+    M: RemoteMessage,
+    M::Result: serde::Serialize + Send,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterHandler<M>, _: &mut Context<Self>) {
+        self.handlers.register(msg.provider);
     }
 }
 
+/// Sent by `Network` to fold its accumulated custom-handler registrations
+/// into a session: once on `PeerConnected`, so a newly connected/reconnected
+/// session doesn't start without handlers registered before it existed, and
+/// again whenever a new type is registered via `RegisterHandler` so already
+/// connected sessions pick it up too. Mirrors how `RaftCreated` is replayed.
+#[derive(Message)]
+pub struct HandlersUpdated(pub HandlerRegistry);
+
+impl Handler<HandlersUpdated> for NodeSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: HandlersUpdated, _: &mut Context<Self>) {
+        self.handlers.merge(&msg.0);
+    }
+}
+
+/// Default for how long a draining session waits for outstanding requests to
+/// finish before it force-stops regardless of what's still pending; override
+/// via [`Listener::with_drain_deadline`].
+const DEFAULT_DRAIN_DEADLINE: Duration = Duration::from_secs(5);
+
 // NodeSession
 pub struct NodeSession {
     hb: Instant,
     network: Addr<Network>,
-    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, SecureNodeCodec>,
     id: Option<NodeId>,
-    handlers: HashMap<&'static str, Arc<dyn RemoteMessageHandler>>,
+    handlers: HandlerRegistry,
     raft: Option<Addr<MemRaft>>,
+    /// `type_id` and accumulated bytes of the streamed request announced by
+    /// `MessageStart`, keyed by `mid`. A `MessageChunk` is a byte-range slice
+    /// of one serialized body, not an independently-decodable document (it's
+    /// the *wire* transfer that's chunked, not the RPC payload itself), so
+    /// chunks are only appended here; the whole body is decoded once, on
+    /// `MessageEnd`.
+    streams: HashMap<u64, (String, Vec<u8>)>,
+    /// `mid`s of `NodeRequest::Message`/`MessageStart` whose response hasn't
+    /// been flushed yet. Consulted when draining to know whether it's safe
+    /// to stop.
+    in_flight: std::collections::HashSet<u64>,
+    /// Set once a close trigger fires; new `Message`/`MessageStart` work is
+    /// refused from then on, and the actor stops once `in_flight` drains (or
+    /// the drain deadline below elapses, whichever comes first).
+    draining: bool,
+    /// Per-peer traffic counters, reported on request via `GetSessionStats`
+    /// and handed off to `Network` on disconnect.
+    stats: TrafficCounters,
+    /// Body codec negotiated with the peer via `Join`; defaults to `Json`
+    /// until then so a peer that reconnects without re-joining still gets a
+    /// codec its bodies can be decoded with.
+    codec: Codec,
+    /// How long `begin_drain` waits for `in_flight` to empty before
+    /// force-stopping; set from the owning `Listener`'s configured deadline.
+    drain_deadline: Duration,
+    /// Byte length of the sealed frame `framed`'s `SecureNodeCodec` most
+    /// recently produced; shares the same cell passed into that codec, so
+    /// `write_out` can account for the real wire size without re-encoding.
+    out_frame_len: Rc<Cell<usize>>,
 }
 
 impl NodeSession {
     fn new(
-        framed: actix::io::FramedWrite<WriteHalf<TcpStream>, NodeCodec>,
+        framed: actix::io::FramedWrite<WriteHalf<TcpStream>, SecureNodeCodec>,
         network: Addr<Network>,
+        drain_deadline: Duration,
+        out_frame_len: Rc<Cell<usize>>,
     ) -> NodeSession {
         NodeSession {
             hb: Instant::now(),
             framed: framed,
             network,
             id: None,
-            handlers: HashMap::new(),
+            handlers: HandlerRegistry::new(),
             raft: None,
+            streams: HashMap::new(),
+            in_flight: std::collections::HashSet::new(),
+            draining: false,
+            stats: TrafficCounters::default(),
+            codec: Codec::default(),
+            drain_deadline,
+            out_frame_len,
+        }
+    }
+
+    /// Accounts for the frame's real on-wire length, reported by
+    /// `SecureNodeCodec::encode` itself, instead of re-serializing `item`
+    /// just to measure it.
+    fn write_out(&mut self, kind: &str, item: NodeResponse) {
+        self.framed.write(item);
+        let len = self.out_frame_len.get();
+        self.stats.record_out(kind, len);
+    }
+
+    /// Stop accepting new request work and either stop immediately (nothing
+    /// outstanding) or wait for `in_flight` to drain, force-stopping after
+    /// `drain_deadline` regardless.
+    fn begin_drain(&mut self, ctx: &mut Context<Self>) {
+        if self.draining {
+            return;
+        }
+        self.draining = true;
+
+        if self.in_flight.is_empty() {
+            ctx.stop();
+            return;
+        }
+
+        ctx.run_later(self.drain_deadline, |_, ctx| {
+            println!("Drain deadline elapsed with requests still in flight, force-stopping");
+            ctx.stop();
+        });
+    }
+
+    /// Called once an in-flight request's response has been flushed; stops
+    /// the session if it was draining and this was the last one outstanding.
+    fn complete(&mut self, mid: u64, ctx: &mut Context<Self>) {
+        self.in_flight.remove(&mid);
+        if self.draining && self.in_flight.is_empty() {
+            ctx.stop();
         }
     }
 
     fn hb(&self, ctx: &mut Context<Self>) {
         ctx.run_interval(Duration::new(1, 0), |act, ctx| {
             if Instant::now().duration_since(act.hb) > Duration::new(10, 0) {
-                println!("Client heartbeat failed, disconnecting!");
-                ctx.stop();
+                println!("Client heartbeat failed, draining connection before disconnecting!");
+                act.begin_drain(ctx);
+                return;
             }
 
             // Reply heartbeat
-            act.framed.write(NodeResponse::Ping);
+            act.write_out("Ping", NodeResponse::Ping);
         });
     }
 }
@@ -129,79 +314,72 @@ impl Actor for NodeSession {
     fn started(&mut self, ctx: &mut Context<Self>) {
         self.hb(ctx);
     }
+
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        if let Some(id) = self.id {
+            let snapshot = self.stats.snapshot(
+                Some(Instant::now().duration_since(self.hb)),
+                self.in_flight.len(),
+            );
+            self.network.do_send(PeerDisconnected(id, snapshot));
+        }
+    }
+}
+
+impl Handler<GetSessionStats> for NodeSession {
+    type Result = MessageResult<GetSessionStats>;
+
+    fn handle(&mut self, _: GetSessionStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.stats.snapshot(
+            Some(Instant::now().duration_since(self.hb)),
+            self.in_flight.len(),
+        ))
+    }
 }
 
-impl actix::io::WriteHandler<std::io::Error> for NodeSession {}
+impl actix::io::WriteHandler<std::io::Error> for NodeSession {
+    fn error(&mut self, err: std::io::Error, ctx: &mut Context<Self>) -> Running {
+        println!("Write error on node session, draining before disconnecting: {}", err);
+        self.begin_drain(ctx);
+        Running::Continue
+    }
+}
+
+/// Sent to begin a graceful shutdown, e.g. from a process signal handler
+/// during a rolling restart: stop accepting new work but let already
+/// in-flight requests finish and flush their replies first.
+#[derive(Message)]
+pub struct Shutdown;
+
+impl Handler<Shutdown> for NodeSession {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Context<Self>) {
+        self.begin_drain(ctx);
+    }
+}
 
 
-struct SendToRaft(String, String);
+struct SendToRaft(String, Codec, Vec<u8>);
 
 impl Message for SendToRaft
 {
-    type Result = Result<String, ()>;
+    type Result = Result<Vec<u8>, String>;
 }
 
 impl Handler<SendToRaft> for NodeSession
 {
-    type Result = Response<String, ()>;
+    type Result = Response<Vec<u8>, String>;
 
-    fn handle(&mut self, msg: SendToRaft, ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: SendToRaft, _: &mut Context<Self>) -> Self::Result {
         let type_id = msg.0;
-        let body = msg.1;
-
-        let res = match type_id.as_str() {
-            "AppendEntriesRequest" => {
-                let raft_msg = serde_json::from_slice::<messages::AppendEntriesRequest<storage::MemoryStorageData>>(body.as_ref()).unwrap();
-                if let Some(ref mut raft) = self.raft {
-                    let future = raft.send(raft_msg)
-                        .map_err(|_| ())
-                        .and_then(|res| {
-                            let res = res.unwrap();
-                            let res_payload = serde_json::to_string::<messages::AppendEntriesResponse>(&res).unwrap();
-                            futures::future::ok(res_payload)
-                        });
-
-                    Response::fut(future)
-                }  else {
-                    Response::reply(Ok("".to_owned()))
-                }
-            },
-            "VoteRequest" => {
-                let raft_msg = serde_json::from_slice::<messages::VoteRequest>(body.as_ref()).unwrap();
-                if let Some(ref mut raft) = self.raft {
-                    let future = raft.send(raft_msg)
-                        .map_err(|_| ())
-                        .and_then(|res| {
-                            let res = res.unwrap();
-                            let res_payload = serde_json::to_string::<messages::VoteResponse>(&res).unwrap();
-                            futures::future::ok(res_payload)
-                        });
-                    Response::fut(future)
-                }  else {
-                    Response::reply(Ok("".to_owned()))
-                }
-            },
-            "InstallSnapshotRequest" => {
-                let raft_msg = serde_json::from_slice::<messages::InstallSnapshotRequest>(body.as_ref()).unwrap();
-                if let Some(ref mut raft) = self.raft {
-                    let future = raft.send(raft_msg)
-                        .map_err(|_| ())
-                        .and_then(|res| {
-                            let res = res.unwrap();
-                            let res_payload = serde_json::to_string::<messages::InstallSnapshotResponse>(&res).unwrap();
-                            futures::future::ok(res_payload)
-                        });
-                    Response::fut(future)
-                } else {
-                    Response::reply(Ok("".to_owned()))
-                }
-            },
-            _ => {
-                Response::reply(Ok("".to_owned()))
-            }
-        };
+        let codec = msg.1;
+        let body = msg.2;
 
-        res
+        match self.handlers.get(&type_id) {
+            Some(handler) => Response::fut(handler.handle(codec, &body)),
+            None => Response::reply(Err(format!("no handler registered for message type `{}`", type_id))),
+        }
     }
 }
 
@@ -210,22 +388,82 @@ impl StreamHandler<NodeRequest, std::io::Error> for NodeSession {
         match msg {
             NodeRequest::Ping => {
                 self.hb = Instant::now();
+                self.stats.record_in("Ping", 0);
                 // println!("Server got ping from {}", self.id.unwrap());
             },
-            NodeRequest::Join(id) => {
-                self.id = Some(id);
-                self.network.do_send(PeerConnected(id, ctx.address()));
+            NodeRequest::Join(_id, codec) => {
+                self.stats.record_in("Join", 0);
+                // The peer's identity is already established by the
+                // secret-handshake performed before this session existed;
+                // a self-asserted id here is no longer trusted. The codec is
+                // just a capability announcement, not an identity claim, so
+                // it's fine to adopt directly: it only changes how this
+                // session's own bodies are encoded/decoded from now on.
+                self.codec = codec;
             },
             NodeRequest::Message(mid, type_id, body) => {
-                let task = actix::fut::wrap_future(ctx.address().send(SendToRaft(type_id, body)))
-                    .map_err(|err, _: &mut NodeSession, _| ())
-                    .and_then(move |res, act, _| {
-                        let payload = res.unwrap();
-                        act.framed.write(NodeResponse::Result(mid, payload));
+                self.stats.record_in(&type_id, body.len());
+                if self.draining {
+                    self.write_out("Error", NodeResponse::Error(mid, "session is draining, retry against another node".to_owned()));
+                    return;
+                }
+                self.in_flight.insert(mid);
+
+                let task = actix::fut::wrap_future(ctx.address().send(SendToRaft(type_id, self.codec, body)))
+                    .map_err(|_, _: &mut NodeSession, _| ())
+                    .and_then(move |res, act, ctx| {
+                        match res {
+                            Ok(payload) => act.write_out("Result", NodeResponse::Result(mid, payload)),
+                            Err(err) => act.write_out("Error", NodeResponse::Error(mid, err)),
+                        }
+                        act.complete(mid, ctx);
                         actix::fut::result(Ok(()))
                     });
                 ctx.spawn(task);
             },
+            NodeRequest::MessageStart(mid, type_id, _len_hint) => {
+                self.stats.record_in("MessageStart", 0);
+                if self.draining {
+                    self.write_out("Error", NodeResponse::Error(mid, "session is draining, retry against another node".to_owned()));
+                    return;
+                }
+                self.in_flight.insert(mid);
+                self.streams.insert(mid, (type_id, Vec::new()));
+                self.write_out("ResultStart", NodeResponse::ResultStart(mid, None));
+            },
+            NodeRequest::MessageChunk(mid, _seq, bytes) => {
+                self.stats.record_in("MessageChunk", bytes.len());
+                if let Some((_, buf)) = self.streams.get_mut(&mid) {
+                    buf.extend_from_slice(&bytes);
+                }
+            },
+            NodeRequest::MessageEnd(mid) => {
+                self.stats.record_in("MessageEnd", 0);
+                match self.streams.remove(&mid) {
+                    // The full body is assembled now, so it's decoded and
+                    // dispatched exactly once, through the same `self.handlers`
+                    // lookup (via `SendToRaft`) the buffered `Message` path
+                    // uses, instead of treating each chunk as its own message.
+                    Some((type_id, body)) => {
+                        let task = actix::fut::wrap_future(ctx.address().send(SendToRaft(type_id, self.codec, body)))
+                            .map_err(|_, _: &mut NodeSession, _| ())
+                            .and_then(move |res, act, ctx| {
+                                match res {
+                                    Ok(payload) => act.write_out("ResultChunk", NodeResponse::ResultChunk(mid, 0, payload)),
+                                    Err(err) => act.write_out("Error", NodeResponse::Error(mid, err)),
+                                }
+                                act.write_out("ResultEnd", NodeResponse::ResultEnd(mid));
+                                act.complete(mid, ctx);
+                                actix::fut::result(Ok(()))
+                            });
+                        ctx.spawn(task);
+                    }
+                    None => {
+                        self.write_out("ResultEnd", NodeResponse::ResultEnd(mid));
+                        self.complete(mid, ctx);
+                    }
+                }
+            },
             _ => ()
         }
     }
@@ -0,0 +1,83 @@
+//! Per-peer traffic accounting. Counters are plain integer adds taken in the
+//! hot path (`TrafficCounters::record_in`/`record_out`); turning them into a
+//! [`TrafficSnapshot`] only happens when something actually asks (via
+//! [`GetTrafficStats`] or `GetSessionStats`) or when a session stops and
+//! hands its final counters to `Network`.
+
+use actix::Message;
+use actix_raft::NodeId;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Point-in-time traffic counters for one peer connection, broken down by
+/// message kind: incoming `"Ping"`, `"Join"`, `"MessageStart"`,
+/// `"MessageChunk"`, `"MessageEnd"`, or a buffered RPC's `type_id` such as
+/// `"AppendEntriesRequest"`; outgoing `"Ping"`, `"Result"`, `"Error"`,
+/// `"ResultStart"`, `"ResultChunk"` or `"ResultEnd"`.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficSnapshot {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: HashMap<String, u64>,
+    pub messages_out: HashMap<String, u64>,
+    pub last_heartbeat_age: Option<Duration>,
+    pub in_flight: usize,
+}
+
+/// Running counters owned by a `NodeSession`. Kept separate from
+/// `TrafficSnapshot` so the hot path only bumps counters in place (one
+/// per-kind map lookup per message), never cloning the per-kind maps.
+#[derive(Default)]
+pub struct TrafficCounters {
+    bytes_in: u64,
+    bytes_out: u64,
+    messages_in: HashMap<String, u64>,
+    messages_out: HashMap<String, u64>,
+}
+
+impl TrafficCounters {
+    pub fn record_in(&mut self, kind: &str, len: usize) {
+        self.bytes_in += len as u64;
+        *self.messages_in.entry(kind.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn record_out(&mut self, kind: &str, len: usize) {
+        self.bytes_out += len as u64;
+        *self.messages_out.entry(kind.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn snapshot(&self, last_heartbeat_age: Option<Duration>, in_flight: usize) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            messages_in: self.messages_in.clone(),
+            messages_out: self.messages_out.clone(),
+            last_heartbeat_age,
+            in_flight,
+        }
+    }
+}
+
+/// Sent to a `NodeSession` to pull its current counters.
+pub struct GetSessionStats;
+
+impl Message for GetSessionStats {
+    type Result = TrafficSnapshot;
+}
+
+/// Sent by a `NodeSession` to `Network` as it stops, so a peer's traffic
+/// history survives the disconnect instead of vanishing with the actor.
+pub struct PeerDisconnected(pub NodeId, pub TrafficSnapshot);
+
+impl Message for PeerDisconnected {
+    type Result = ();
+}
+
+/// Sent to `Network` to get a full per-peer traffic snapshot: live sessions
+/// are polled for their current counters, merged with the last known stats
+/// for peers that have since disconnected.
+pub struct GetTrafficStats;
+
+impl Message for GetTrafficStats {
+    type Result = Result<HashMap<NodeId, TrafficSnapshot>, ()>;
+}
@@ -1,13 +1,17 @@
 mod codec;
+pub mod handshake;
+pub mod listener;
+mod metrics;
 mod network;
 mod node;
 mod recipient;
 pub mod remote;
-mod session;
 
-pub use self::codec::{ClientNodeCodec, NodeCodec, NodeRequest, NodeResponse};
+pub use self::codec::{ClientNodeCodec, Codec, NodeCodec, NodeRequest, NodeResponse, SecureNodeCodec};
+pub use self::handshake::{Handshaken, Identity, NetworkKey, SessionKey};
+pub use self::listener::NodeSession;
+pub use self::metrics::{GetTrafficStats, TrafficSnapshot};
 pub use self::network::{
     DiscoverNodes, DistributeMessage, GetCurrentLeader, GetNode, GetNodeAddr, GetNodeById, Network, PeerConnected, SetRaft, DistributeAndWait};
 pub use self::node::Node;
 pub use self::recipient::{HandlerRegistry, Provider, RemoteMessageHandler};
-pub use self::session::NodeSession;
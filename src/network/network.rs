@@ -0,0 +1,252 @@
+use actix::prelude::*;
+use actix_raft::NodeId;
+use futures::Future;
+use std::collections::HashMap;
+
+use crate::raft::MemRaft;
+use crate::network::{
+    listener::{HandlersUpdated, RaftCreated},
+    metrics::{GetSessionStats, GetTrafficStats, PeerDisconnected, TrafficSnapshot},
+    remote::{HandlerRegistry, RegisterHandler, RemoteMessage},
+    NodeSession,
+};
+
+/// Directory of known peers and the sessions currently connected to them.
+/// Owns nothing about Raft itself beyond holding the `Addr<MemRaft>` so it
+/// can be handed to new sessions as they connect.
+pub struct Network {
+    id: NodeId,
+    peer_addrs: HashMap<NodeId, String>,
+    sessions: HashMap<NodeId, Addr<NodeSession>>,
+    raft: Option<Addr<MemRaft>>,
+    leader: Option<NodeId>,
+    /// Traffic stats for peers that have since disconnected, kept around so
+    /// `GetTrafficStats` doesn't lose history the moment a link drops.
+    disconnected_stats: HashMap<NodeId, TrafficSnapshot>,
+    /// Every `RegisterHandler` registration seen so far, so it can be replayed
+    /// onto sessions that didn't exist yet when it was registered — a
+    /// `NodeSession` starts with an empty `HandlerRegistry`, so without this
+    /// a custom handler would only ever reach the one session it was
+    /// originally sent to.
+    handlers: HandlerRegistry,
+}
+
+impl Network {
+    pub fn new(id: NodeId) -> Network {
+        Network {
+            id,
+            peer_addrs: HashMap::new(),
+            sessions: HashMap::new(),
+            raft: None,
+            leader: None,
+            disconnected_stats: HashMap::new(),
+            handlers: HandlerRegistry::new(),
+        }
+    }
+}
+
+impl Actor for Network {
+    type Context = Context<Self>;
+}
+
+/// Registers a peer's known listen address, e.g. from static cluster config.
+#[derive(Message)]
+pub struct DiscoverNodes(pub Vec<(NodeId, String)>);
+
+impl Handler<DiscoverNodes> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: DiscoverNodes, _: &mut Context<Self>) {
+        for (id, addr) in msg.0 {
+            self.peer_addrs.insert(id, addr);
+        }
+    }
+}
+
+/// Addressed to a specific peer with a message type/body. Replies `Ok("")`
+/// if a session to that peer is connected and `Err(())` otherwise; the
+/// type/body are not forwarded to the session.
+pub struct DistributeMessage(pub NodeId, pub String, pub String);
+
+impl Message for DistributeMessage {
+    type Result = Result<String, ()>;
+}
+
+impl Handler<DistributeMessage> for Network {
+    type Result = Response<String, ()>;
+
+    fn handle(&mut self, msg: DistributeMessage, _: &mut Context<Self>) -> Self::Result {
+        match self.sessions.get(&msg.0) {
+            Some(_session) => Response::reply(Ok("".to_owned())),
+            None => Response::reply(Err(())),
+        }
+    }
+}
+
+/// Handled exactly like `DistributeMessage`: same reply, and nothing is
+/// forwarded to the session or waited on.
+pub struct DistributeAndWait(pub NodeId, pub String, pub String);
+
+impl Message for DistributeAndWait {
+    type Result = Result<String, ()>;
+}
+
+impl Handler<DistributeAndWait> for Network {
+    type Result = Response<String, ()>;
+
+    fn handle(&mut self, msg: DistributeAndWait, ctx: &mut Context<Self>) -> Self::Result {
+        Handler::<DistributeMessage>::handle(self, DistributeMessage(msg.0, msg.1, msg.2), ctx)
+    }
+}
+
+pub struct GetCurrentLeader;
+
+impl Message for GetCurrentLeader {
+    type Result = Option<NodeId>;
+}
+
+impl Handler<GetCurrentLeader> for Network {
+    type Result = MessageResult<GetCurrentLeader>;
+
+    fn handle(&mut self, _: GetCurrentLeader, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.leader)
+    }
+}
+
+pub struct GetNode(pub NodeId);
+
+impl Message for GetNode {
+    type Result = Option<Addr<NodeSession>>;
+}
+
+impl Handler<GetNode> for Network {
+    type Result = MessageResult<GetNode>;
+
+    fn handle(&mut self, msg: GetNode, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.sessions.get(&msg.0).cloned())
+    }
+}
+
+pub struct GetNodeAddr(pub NodeId);
+
+impl Message for GetNodeAddr {
+    type Result = Option<String>;
+}
+
+impl Handler<GetNodeAddr> for Network {
+    type Result = MessageResult<GetNodeAddr>;
+
+    fn handle(&mut self, msg: GetNodeAddr, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.peer_addrs.get(&msg.0).cloned())
+    }
+}
+
+pub struct GetNodeById(pub NodeId);
+
+impl Message for GetNodeById {
+    type Result = bool;
+}
+
+impl Handler<GetNodeById> for Network {
+    type Result = MessageResult<GetNodeById>;
+
+    fn handle(&mut self, msg: GetNodeById, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.sessions.contains_key(&msg.0))
+    }
+}
+
+/// Sent by `NodeSession` once the secret-handshake has authenticated a
+/// peer's id, so `Network` can route outbound messages to it.
+#[derive(Message)]
+pub struct PeerConnected(pub NodeId, pub Addr<NodeSession>);
+
+impl Handler<PeerConnected> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerConnected, _: &mut Context<Self>) {
+        if let Some(ref raft) = self.raft {
+            msg.1.do_send(RaftCreated(raft.clone()));
+        }
+        msg.1.do_send(HandlersUpdated(self.handlers.clone()));
+        self.sessions.insert(msg.0, msg.1);
+    }
+}
+
+/// Registers a `Provider` for a custom `RemoteMessage` type across the whole
+/// cluster connection, not just whichever session happens to be live right
+/// now: `Network` remembers the registration (so it can replay it onto
+/// sessions that connect later, the same way `raft` is replayed via
+/// `RaftCreated`) and pushes it out to every session already connected.
+impl<M> Handler<RegisterHandler<M>> for Network
+where
+    M: RemoteMessage,
+    M::Result: serde::Serialize + Send,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: RegisterHandler<M>, _: &mut Context<Self>) {
+        self.handlers.register(msg.provider);
+        for session in self.sessions.values() {
+            session.do_send(HandlersUpdated(self.handlers.clone()));
+        }
+    }
+}
+
+/// Installs the cluster's Raft actor and pushes it out to every session
+/// already connected (new sessions pick it up via `PeerConnected` above).
+#[derive(Message)]
+pub struct SetRaft(pub Addr<MemRaft>);
+
+impl Handler<SetRaft> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRaft, _: &mut Context<Self>) {
+        for session in self.sessions.values() {
+            session.do_send(RaftCreated(msg.0.clone()));
+        }
+        self.raft = Some(msg.0);
+    }
+}
+
+impl Handler<PeerDisconnected> for Network {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerDisconnected, _: &mut Context<Self>) {
+        self.sessions.remove(&msg.0);
+        self.disconnected_stats.insert(msg.0, msg.1);
+    }
+}
+
+impl Handler<GetTrafficStats> for Network {
+    type Result = Response<HashMap<NodeId, TrafficSnapshot>, ()>;
+
+    fn handle(&mut self, _: GetTrafficStats, _: &mut Context<Self>) -> Self::Result {
+        let mut stats = self.disconnected_stats.clone();
+
+        // A session whose mailbox errors (e.g. it's mid-stop) shouldn't drag
+        // the whole snapshot down with it; fold its failure into `None` and
+        // keep going rather than letting `join_all` fail the batch.
+        let live = self
+            .sessions
+            .iter()
+            .map(|(id, session)| {
+                let id = *id;
+                session
+                    .send(GetSessionStats)
+                    .map(move |snapshot| Some((id, snapshot)))
+                    .or_else(|_| futures::future::ok(None))
+            })
+            .collect::<Vec<_>>();
+
+        let future = futures::future::join_all(live)
+            .map_err(|_: ()| ())
+            .map(move |results| {
+                for (id, snapshot) in results.into_iter().flatten() {
+                    stats.insert(id, snapshot);
+                }
+                stats
+            });
+
+        Response::fut(future)
+    }
+}
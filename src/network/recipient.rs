@@ -0,0 +1,152 @@
+//! Generic dispatch for remote messages carried inside `NodeRequest::Message`.
+//!
+//! Each distinct RPC or state-machine command is a [`RemoteMessage`] keyed by
+//! a `type_id`; a [`Provider`] supplies whatever actually processes it (in
+//! practice an `Addr<A>` for the actor whose `Handler<M>` impl does the
+//! work). [`HandlerRegistry`] erases the message type behind
+//! [`RemoteMessageHandler`] so `NodeSession` can route an incoming frame by
+//! its `type_id` string without knowing every concrete type at compile time.
+
+use actix::{Actor, Addr, Handler, Message};
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::network::codec::Codec;
+
+/// A message type that can arrive as the body of a `NodeRequest::Message`:
+/// decodable with the session's negotiated `Codec`, encodable back the same
+/// way for the reply, and tagged
+/// with the wire `type_id` it's dispatched under.
+pub trait RemoteMessage: Message + serde::de::DeserializeOwned + Send + 'static
+where
+    Self::Result: serde::Serialize + Send,
+{
+    const TYPE_ID: &'static str;
+}
+
+/// Whatever actually processes a `RemoteMessage` once it's been decoded off
+/// the wire. Implemented for `Addr<A>` of any actor with a matching
+/// `Handler<M>`, so registering a handler is just registering that actor's
+/// address.
+pub trait Provider<M: RemoteMessage>: Send + Sync
+where
+    M::Result: serde::Serialize + Send,
+{
+    fn send(&self, msg: M) -> Box<dyn Future<Item = M::Result, Error = ()> + Send>;
+}
+
+impl<A, M> Provider<M> for Addr<A>
+where
+    A: Actor<Context = actix::Context<A>> + Handler<M>,
+    M: RemoteMessage,
+    M::Result: serde::Serialize + Send,
+{
+    fn send(&self, msg: M) -> Box<dyn Future<Item = M::Result, Error = ()> + Send> {
+        Box::new(Addr::send(self, msg).map_err(|_| ()))
+    }
+}
+
+/// Type-erased entry point for one registered `type_id`: decode the body,
+/// route it to the `Provider`, encode the response. Stored behind
+/// `Arc<dyn RemoteMessageHandler>` so `HandlerRegistry` can hold handlers for
+/// unrelated message types in the same map. `codec` is whatever the session
+/// negotiated with the peer on `Join`; body bytes are opaque until decoded
+/// with it.
+pub trait RemoteMessageHandler: Send + Sync {
+    fn handle(&self, codec: Codec, body: &[u8]) -> Box<dyn Future<Item = Vec<u8>, Error = String> + Send>;
+}
+
+struct TypedHandler<M: RemoteMessage>
+where
+    M::Result: serde::Serialize + Send,
+{
+    provider: Arc<dyn Provider<M>>,
+}
+
+impl<M: RemoteMessage> RemoteMessageHandler for TypedHandler<M>
+where
+    M::Result: serde::Serialize + Send,
+{
+    fn handle(&self, codec: Codec, body: &[u8]) -> Box<dyn Future<Item = Vec<u8>, Error = String> + Send> {
+        let msg = match codec.decode::<M>(body) {
+            Ok(msg) => msg,
+            Err(err) => {
+                return Box::new(futures::future::err(format!(
+                    "failed to decode {}: {}",
+                    M::TYPE_ID,
+                    err
+                )))
+            }
+        };
+
+        let provider = self.provider.clone();
+        let future = provider
+            .send(msg)
+            .map_err(|_| "handler actor failed to process message".to_owned())
+            .and_then(move |res| {
+                codec.encode(&res).map_err(|err| format!("failed to encode response: {}", err))
+            });
+        Box::new(future)
+    }
+}
+
+/// Maps `type_id` to the handler registered for it. `NodeSession` holds one
+/// of these and consults it for every `NodeRequest::Message` instead of
+/// hardcoding a match over known RPC types.
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<&'static str, Arc<dyn RemoteMessageHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> HandlerRegistry {
+        HandlerRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register<M>(&mut self, provider: Arc<dyn Provider<M>>)
+    where
+        M: RemoteMessage,
+        M::Result: serde::Serialize + Send,
+    {
+        self.handlers
+            .insert(M::TYPE_ID, Arc::new(TypedHandler { provider }));
+    }
+
+    pub fn get(&self, type_id: &str) -> Option<Arc<dyn RemoteMessageHandler>> {
+        self.handlers.get(type_id).cloned()
+    }
+
+    /// Copies every entry of `other` into `self`, overwriting any existing
+    /// entry for the same `type_id`. Used by `NodeSession` to fold the
+    /// registry `Network` replays via `HandlersUpdated` into its own.
+    pub fn merge(&mut self, other: &HandlerRegistry) {
+        for (type_id, handler) in other.handlers.iter() {
+            self.handlers.insert(type_id, handler.clone());
+        }
+    }
+}
+
+/// Sent to register a `Provider` for a given `RemoteMessage` type, e.g. so an
+/// application can route its own state-machine commands over the same
+/// sessions Raft's RPCs already use. Send this to `Network`, not to an
+/// individual `NodeSession`: `Network` remembers the registration and
+/// replays it onto every session, including ones that connect later, the
+/// same way it already does for `raft` via `RaftCreated`.
+pub struct RegisterHandler<M>
+where
+    M: RemoteMessage,
+    M::Result: serde::Serialize + Send,
+{
+    pub provider: Arc<dyn Provider<M>>,
+}
+
+impl<M> Message for RegisterHandler<M>
+where
+    M: RemoteMessage,
+    M::Result: serde::Serialize + Send,
+{
+    type Result = ();
+}
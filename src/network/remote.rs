@@ -0,0 +1,7 @@
+//! Public entry point for the remote-message dispatch machinery. Application
+//! crates register their own [`RemoteMessage`] types here rather than
+//! reaching into `network`'s private modules directly.
+
+pub use crate::network::recipient::{
+    HandlerRegistry, Provider, RegisterHandler, RemoteMessage, RemoteMessageHandler,
+};